@@ -4,7 +4,10 @@ extern crate pkg_config;
 extern crate semver;
 extern crate serde;
 extern crate serde_xml_rs;
+extern crate sha2;
 extern crate tar;
+#[cfg(feature = "generate-bindings")]
+extern crate bindgen;
 
 use std::env::{
     self,
@@ -12,9 +15,11 @@ use std::env::{
 };
 use std::error::Error;
 use std::fs::{self, File};
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
 
 use curl::easy::Easy;
 #[cfg(not(target_env = "msvc"))]
@@ -22,6 +27,7 @@ use flate2::read::GzDecoder;
 use semver::Version;
 use serde::Deserialize;
 use serde_xml_rs::from_reader;
+use sha2::{Digest, Sha256};
 #[cfg(not(target_env = "msvc"))]
 use tar::Archive;
 #[cfg(target_env = "msvc")]
@@ -33,10 +39,92 @@ const REPOSITORY: &'static str = "https://github.com/tensorflow/tensorflow.git";
 const FRAMEWORK_TARGET: &'static str = "tensorflow:libtensorflow_framework";
 const TARGET: &'static str = "tensorflow:libtensorflow";
 // `VERSION` and `TAG` are separate because the tag is not always `'v' + VERSION`.
-// `VERSION` is not currently used for nightly builds
-// const VERSION: &'static str = "1.15.0";
+const VERSION: &'static str = "2.2.0";
 const TAG: &'static str = "v2.2.0";
 const MIN_BAZEL: &'static str = "0.5.4";
+const DEFAULT_MIRROR_URL: &'static str = "https://storage.googleapis.com/libtensorflow-nightly";
+
+// Base URL for both the nightly bucket listing and the nightly asset download, overridable via
+// `TF_RUST_MIRROR_URL` for users behind corporate proxies or regional mirrors.
+fn mirror_base_url() -> String {
+    env::var("TF_RUST_MIRROR_URL").unwrap_or_else(|_| DEFAULT_MIRROR_URL.to_string())
+}
+
+// Which of the available build strategies to use, selected via `TF_RUST_STRATEGY`. Replaces the
+// old `TF_RUST_BUILD_FROM_SRC` boolean and the implicit "nightly if x86_64 else source" default.
+enum Strategy {
+    // Only look for a system-installed library via pkg-config / `check_windows_lib`. Error out if
+    // it can't be found rather than silently falling back to a download or a source build.
+    System,
+    // Download the latest nightly prebuilt binary from the GCS bucket (previous default
+    // behavior).
+    DownloadNightly,
+    // Download the pinned stable release tarball matching `VERSION`/`TAG`.
+    DownloadRelease,
+    // Force a Bazel build from source.
+    Source,
+}
+
+impl Strategy {
+    fn from_env() -> Strategy {
+        match env::var("TF_RUST_STRATEGY") {
+            Ok(ref s) if s == "system" => Strategy::System,
+            Ok(ref s) if s == "download-nightly" => Strategy::DownloadNightly,
+            Ok(ref s) if s == "download-release" => Strategy::DownloadRelease,
+            Ok(ref s) if s == "source" => Strategy::Source,
+            Ok(s) => panic!(
+                "Unknown TF_RUST_STRATEGY {:?}; expected one of: system, download-nightly, \
+                 download-release, source",
+                s
+            ),
+            Err(_) => {
+                // No explicit strategy requested: keep the historical auto-detect behavior of
+                // preferring a system install via pkg-config, then a nightly prebuilt where one is
+                // known to exist, falling back to source everywhere else. An explicit strategy
+                // (handled above) is authoritative and skips this pkg-config probe entirely, so
+                // e.g. TF_RUST_STRATEGY=source can't be silently overridden by a pkg-config install.
+                // On MSVC, main() still runs check_windows_lib before any strategy, so a
+                // tensorflow.lib found on PATH is used whatever TF_RUST_STRATEGY says.
+                if pkg_config::probe_library(LIBRARY).is_ok() {
+                    return Strategy::System;
+                }
+                if Architecture::from_rust_arch(env::consts::ARCH).is_some()
+                    && (env::consts::OS == "linux"
+                        || env::consts::OS == "macos"
+                        || env::consts::OS == "windows")
+                {
+                    Strategy::DownloadNightly
+                } else {
+                    Strategy::Source
+                }
+            }
+        }
+    }
+}
+
+// Recognizes the architectures for which prebuilt libtensorflow assets exist and maps them to the
+// bucket's naming (`x86_64` vs `arm64`).
+enum Architecture {
+    X86_64,
+    Arm64,
+}
+
+impl Architecture {
+    fn from_rust_arch(arch: &str) -> Option<Architecture> {
+        match arch {
+            "x86_64" => Some(Architecture::X86_64),
+            "aarch64" | "arm64" => Some(Architecture::Arm64),
+            _ => None,
+        }
+    }
+
+    fn bucket_name(&self) -> &'static str {
+        match self {
+            Architecture::X86_64 => "x86_64",
+            Architecture::Arm64 => "arm64",
+        }
+    }
+}
 
 macro_rules! get(($name:expr) => (ok!(env::var($name))));
 macro_rules! ok(($expression:expr) => ($expression.unwrap()));
@@ -48,32 +136,47 @@ macro_rules! log {
 macro_rules! log_var(($var:ident) => (log!(concat!(stringify!($var), " = {:?}"), $var)));
 
 fn main() {
-    if check_windows_lib() {
-        log!("Returning early because {} was already found", LIBRARY);
+    // TF_RUST_LIB_LOCATION short-circuits everything else so air-gapped CI and reproducible
+    // packaging can point directly at a vendored library.
+    if let Ok(dir) = env::var("TF_RUST_LIB_LOCATION") {
+        use_vendored_lib(&PathBuf::from(dir));
         return;
     }
 
-    // Note that pkg_config will print cargo:rustc-link-lib and cargo:rustc-link-search as
-    // appropriate if the library is found.
-    if pkg_config::probe_library(LIBRARY).is_ok() {
+    if check_windows_lib() {
         log!("Returning early because {} was already found", LIBRARY);
+        require_no_bindgen("a system library found on PATH");
         return;
     }
 
-    let force_src = match env::var("TF_RUST_BUILD_FROM_SRC") {
-        Ok(s) => s == "true",
-        Err(_) => false,
-    };
-
-    if !force_src
-        && env::consts::ARCH == "x86_64"
-        && (env::consts::OS == "linux"
-            || env::consts::OS == "macos"
-            || env::consts::OS == "windows")
-    {
-        install_prebuilt();
-    } else {
-        build_from_src();
+    match Strategy::from_env() {
+        // Note that pkg_config will print cargo:rustc-link-lib and cargo:rustc-link-search as
+        // appropriate if the library is found.
+        Strategy::System => {
+            if pkg_config::probe_library(LIBRARY).is_ok() {
+                log!("Returning early because {} was already found", LIBRARY);
+                require_no_bindgen("a system library found via pkg-config");
+                return;
+            }
+            panic!(
+                "TF_RUST_STRATEGY=system requires {} to be discoverable via pkg-config (or \
+                 already on PATH on Windows), but it was not found",
+                LIBRARY
+            )
+        }
+        Strategy::DownloadNightly => {
+            if !install_prebuilt(Channel::Nightly) {
+                log!("No prebuilt nightly asset found for this target; falling back to source build");
+                build_from_src();
+            }
+        }
+        Strategy::DownloadRelease => {
+            if !install_prebuilt(Channel::Release) {
+                log!("No prebuilt release asset found for this target; falling back to source build");
+                build_from_src();
+            }
+        }
+        Strategy::Source => build_from_src(),
     }
 }
 
@@ -98,6 +201,41 @@ fn check_windows_lib() -> bool {
     false
 }
 
+// Links directly against a pre-vetted or system-installed shared library in `dir`, supplied via
+// `TF_RUST_LIB_LOCATION`, without downloading or building anything.
+fn use_vendored_lib(dir: &Path) {
+    // On MSVC, linking needs the import library (`tensorflow.lib`), not the runtime `.dll` —
+    // mirroring check_windows_lib's own convention above.
+    #[cfg(target_env = "msvc")]
+    let library_file = dir.join(format!("{}.lib", LIBRARY));
+    #[cfg(not(target_env = "msvc"))]
+    let library_file = dir.join(format!("{}{}.{}", DLL_PREFIX, LIBRARY, DLL_EXTENSION));
+    if !library_file.exists() {
+        panic!(
+            "TF_RUST_LIB_LOCATION={} does not contain {}",
+            dir.display(),
+            library_file.display()
+        );
+    }
+    #[cfg(not(target_env = "msvc"))]
+    {
+        let framework_library_file = dir.join(format!("lib{}.{}", FRAMEWORK_LIBRARY, DLL_EXTENSION));
+        if !framework_library_file.exists() {
+            panic!(
+                "TF_RUST_LIB_LOCATION={} does not contain {}",
+                dir.display(),
+                framework_library_file.display()
+            );
+        }
+    }
+
+    println!("cargo:rustc-link-search=native={}", dir.display());
+    #[cfg(not(target_env = "msvc"))]
+    println!("cargo:rustc-link-lib=dylib={}", FRAMEWORK_LIBRARY);
+    println!("cargo:rustc-link-lib=dylib={}", LIBRARY);
+    require_no_bindgen("TF_RUST_LIB_LOCATION");
+}
+
 fn remove_suffix(value: &mut String, suffix: &str) {
     if value.ends_with(suffix) {
         let n = value.len();
@@ -157,19 +295,29 @@ struct BucketObject {
     generation: u64,
 }
 
+// Which prebuilt binary channel to resolve a download URL from.
+enum Channel {
+    // The latest nightly build, resolved by listing the nightly GCS bucket.
+    Nightly,
+    // The pinned stable release matching `VERSION`/`TAG`, at a deterministic URL.
+    Release,
+}
+
 // Get the URL for the latest pre-compiled nightly C lib on this system
-fn get_latest_nightly_url(os: &str, proc_type: &str, ext: &str) -> String {
+fn get_latest_nightly_url(os: &str, proc_type: &str, ext: &str) -> Option<String> {
+    let arch = Architecture::from_rust_arch(env::consts::ARCH)
+        .unwrap_or_else(|| panic!("Unsupported architecture {}", env::consts::ARCH));
     let filename = format!(
         "libtensorflow-{}-{}-{}{}",
         proc_type,
         os,
-        env::consts::ARCH,
+        arch.bucket_name(),
         ext
     );
     log_var!(filename);
 
     // Fetch available builds from storage
-    let base_url = "https://storage.googleapis.com/libtensorflow-nightly";
+    let base_url = mirror_base_url();
     let mut res = Vec::new();
     let mut easy = Easy::new();
     easy.url(&base_url).unwrap();
@@ -192,17 +340,253 @@ fn get_latest_nightly_url(os: &str, proc_type: &str, ext: &str) -> String {
         .filter(|obj| obj.key.ends_with(&filename))
         .collect::<Vec<_>>();
     objs.sort_by_key(|obj| obj.generation);
-    format!(
-        "{}/{}",
-        base_url,
-        objs.last()
-            .unwrap_or_else(|| panic!("Unable to find nightly build for system"))
-            .key
-    )
+    objs.last()
+        .map(|obj| format!("{}/{}", base_url, obj.key))
+}
+
+// Get the URL for the pinned stable release C lib on this system, or `None` if no such asset is
+// published for this arch/OS (unlike the nightly bucket listing, this URL is constructed rather
+// than discovered, so its existence has to be checked explicitly).
+fn get_release_url(os: &str, proc_type: &str, ext: &str) -> Option<String> {
+    let arch = Architecture::from_rust_arch(env::consts::ARCH)
+        .unwrap_or_else(|| panic!("Unsupported architecture {}", env::consts::ARCH));
+    let url = format!(
+        "https://storage.googleapis.com/tensorflow/libtensorflow/libtensorflow-{}-{}-{}-{}{}",
+        proc_type,
+        os,
+        arch.bucket_name(),
+        VERSION,
+        ext
+    );
+    if url_exists(&url) {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+// HEAD request to check whether `url` resolves to an existing object.
+fn url_exists(url: &str) -> bool {
+    let mut easy = Easy::new();
+    easy.url(url).unwrap();
+    easy.nobody(true).unwrap();
+    if easy.perform().is_err() {
+        return false;
+    }
+    matches!(easy.response_code(), Ok(200))
+}
+
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+
+// Downloads `url` to `file_name`, retrying with exponential backoff on failure. A partially
+// downloaded file is kept at `{file_name}.partial` and resumed via an HTTP Range request rather
+// than restarted from scratch, except when the server responds with something other than 200/206
+// (in which case the partial file can't be trusted and is discarded before retrying).
+fn download_with_retry(url: &str, file_name: &Path) {
+    let partial_file_name = PathBuf::from(format!("{}.partial", file_name.display()));
+
+    for attempt in 1..=MAX_DOWNLOAD_ATTEMPTS {
+        let existing_len = fs::metadata(&partial_file_name).map(|m| m.len()).unwrap_or(0);
+
+        let f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial_file_name)
+            .unwrap();
+        let mut writer = BufWriter::new(f);
+
+        let mut easy = Easy::new();
+        easy.url(url).unwrap();
+        if existing_len > 0 {
+            log!(
+                "Resuming download of {:?} from byte {}",
+                partial_file_name,
+                existing_len
+            );
+            easy.resume_from(existing_len).unwrap();
+        }
+        easy.write_function(move |data| Ok(writer.write(data).unwrap()))
+            .unwrap();
+
+        match easy.perform() {
+            Ok(()) => {
+                let response_code = easy.response_code().unwrap();
+                match response_code {
+                    206 => {
+                        fs::rename(&partial_file_name, file_name).unwrap();
+                        return;
+                    }
+                    200 => {
+                        if existing_len > 0 {
+                            // The server didn't honor the Range request and sent the full body
+                            // from byte 0, which got appended after the stale partial bytes we
+                            // already had on disk. The genuine content is the tail of the file
+                            // following those stale bytes.
+                            log!(
+                                "Server returned 200 instead of 206 while resuming {:?}; \
+                                 discarding stale partial bytes",
+                                partial_file_name
+                            );
+                            let body = fs::read(&partial_file_name).unwrap();
+                            fs::write(&partial_file_name, &body[existing_len as usize..]).unwrap();
+                        }
+                        fs::rename(&partial_file_name, file_name).unwrap();
+                        return;
+                    }
+                    _ => {
+                        log!(
+                            "Download attempt {}/{} for {} got unexpected response code {}",
+                            attempt, MAX_DOWNLOAD_ATTEMPTS, url, response_code
+                        );
+                        fs::remove_file(&partial_file_name).ok();
+                    }
+                }
+            }
+            Err(e) => {
+                log!(
+                    "Download attempt {}/{} for {} failed: {}",
+                    attempt, MAX_DOWNLOAD_ATTEMPTS, url, e
+                );
+            }
+        }
+
+        if attempt == MAX_DOWNLOAD_ATTEMPTS {
+            panic!(
+                "Failed to download {} after {} attempts",
+                url, MAX_DOWNLOAD_ATTEMPTS
+            );
+        }
+        let backoff = Duration::from_secs(1 << attempt.min(6));
+        log!("Retrying {} in {:?}", url, backoff);
+        thread::sleep(backoff);
+    }
+}
+
+// Verifies the SHA-256 of a downloaded file against an expected digest, deleting the file and
+// panicking on mismatch so that a corrupted or truncated download can't silently poison the
+// `TF_RUST_DOWNLOAD_DIR` cache.
+fn verify_sha256(file_name: &Path, binary_url: &str) {
+    let expected = match env::var("TF_RUST_EXPECTED_SHA256") {
+        Ok(s) => s,
+        Err(_) => fetch_expected_sha256(binary_url),
+    };
+    let actual = compute_sha256(file_name);
+    if !actual.eq_ignore_ascii_case(&expected) {
+        fs::remove_file(file_name).unwrap();
+        panic!(
+            "SHA-256 mismatch for {}: expected {}, got {}",
+            file_name.display(),
+            expected,
+            actual
+        );
+    }
+    log!("Verified SHA-256 of {}: {}", file_name.display(), actual);
+}
+
+// Fetches the expected SHA-256 digest from the `.sha256` sibling of `binary_url`.
+fn fetch_expected_sha256(binary_url: &str) -> String {
+    let sha_url = format!("{}.sha256", binary_url);
+    log_var!(sha_url);
+    let mut res = Vec::new();
+    let mut easy = Easy::new();
+    easy.url(&sha_url).unwrap();
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|data| {
+                res.extend_from_slice(data);
+                Ok(data.len())
+            })
+            .unwrap();
+        transfer.perform().unwrap();
+    }
+    let response_code = easy.response_code().unwrap();
+    if response_code != 200 {
+        panic!(
+            "Unexpected response code {} fetching expected SHA-256 from {}",
+            response_code, sha_url
+        );
+    }
+    String::from_utf8(res)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .unwrap_or_else(|| panic!("Empty SHA-256 digest file at {}", sha_url))
+        .to_string()
+}
+
+fn compute_sha256(path: &Path) -> String {
+    let mut file = File::open(path).unwrap();
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).unwrap();
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Generates `bindings.rs` in `OUT_DIR` from `c_api.h` under `include_dir`, keeping bindings
+// automatically in sync with whatever version was downloaded or built instead of drifting from a
+// hand-maintained, committed bindings file.
+#[cfg(feature = "generate-bindings")]
+fn generate_bindings(include_dir: &Path) {
+    let header = include_dir.join("tensorflow").join("c").join("c_api.h");
+    log_var!(header);
+    let builder = bindgen::Builder::default()
+        .header(header.to_str().unwrap())
+        .clang_arg(format!("-I{}", include_dir.display()))
+        .allowlist_type("TF_.*")
+        .allowlist_function("TF_.*")
+        .allowlist_var("TF_.*");
+    let builder = add_msvc_includes(builder);
+
+    let bindings = builder.generate().expect("Unable to generate bindings");
+    let out_path = PathBuf::from(&get!("OUT_DIR")).join("bindings.rs");
+    bindings
+        .write_to_file(&out_path)
+        .expect("Couldn't write bindings.rs");
+}
+
+#[cfg(not(feature = "generate-bindings"))]
+fn generate_bindings(_include_dir: &Path) {}
+
+// `generate-bindings` needs install_prebuilt()/build_from_src() to run so bindgen has a known
+// include/ directory to work from. Paths that skip straight to linking (a system library found
+// via check_windows_lib/pkg-config, or TF_RUST_LIB_LOCATION) can't produce OUT_DIR/bindings.rs, so
+// fail loudly here instead of leaving downstream `include!("bindings.rs")` to fail with a
+// confusing "file not found".
+#[cfg(feature = "generate-bindings")]
+fn require_no_bindgen(context: &str) {
+    panic!(
+        "the `generate-bindings` feature requires downloading or building libtensorflow so \
+         bindgen has headers to work from, but {} skipped that step; unset TF_RUST_STRATEGY=system \
+         / TF_RUST_LIB_LOCATION, or disable the `generate-bindings` feature",
+        context
+    );
+}
+
+#[cfg(not(feature = "generate-bindings"))]
+fn require_no_bindgen(_context: &str) {}
+
+#[cfg(all(feature = "generate-bindings", target_env = "msvc"))]
+fn add_msvc_includes(builder: bindgen::Builder) -> bindgen::Builder {
+    match env::var("INCLUDE") {
+        Ok(include) => include
+            .split(';')
+            .filter(|p| !p.is_empty())
+            .fold(builder, |b, p| b.clang_arg(format!("-I{}", p))),
+        Err(_) => builder,
+    }
+}
+
+#[cfg(all(feature = "generate-bindings", not(target_env = "msvc")))]
+fn add_msvc_includes(builder: bindgen::Builder) -> bindgen::Builder {
+    builder
 }
 
 // Downloads and unpacks a prebuilt binary. Only works for certain platforms.
-fn install_prebuilt() {
+fn install_prebuilt(channel: Channel) -> bool {
     let os = match env::consts::OS {
         "macos" => "darwin",
         x => x,
@@ -217,7 +601,16 @@ fn install_prebuilt() {
     #[cfg(not(target_env = "msvc"))]
     let ext = ".tar.gz";
 
-    let binary_url = get_latest_nightly_url(os, proc_type, ext);
+    let binary_url = match channel {
+        Channel::Nightly => match get_latest_nightly_url(os, proc_type, ext) {
+            Some(url) => url,
+            None => return false,
+        },
+        Channel::Release => match get_release_url(os, proc_type, ext) {
+            Some(url) => url,
+            None => return false,
+        },
+    };
     log_var!(binary_url);
     let short_file_name = binary_url.split("/").last().unwrap();
     let mut base_name = short_file_name.to_string();
@@ -233,23 +626,13 @@ fn install_prebuilt() {
     let file_name = download_dir.join(short_file_name);
     log_var!(file_name);
 
-    // Download the tarball.
+    // Download the tarball. Only verify its SHA-256 right after a fresh download: a cached file
+    // from a previous successful build was already verified once, so re-hashing it (and, absent
+    // TF_RUST_EXPECTED_SHA256, re-fetching the `.sha256` sibling over the network) on every
+    // incremental `cargo build` would add real cost for no benefit.
     if !file_name.exists() {
-        let f = File::create(&file_name).unwrap();
-        let mut writer = BufWriter::new(f);
-        let mut easy = Easy::new();
-        easy.url(&binary_url).unwrap();
-        easy.write_function(move |data| Ok(writer.write(data).unwrap()))
-            .unwrap();
-        easy.perform().unwrap();
-
-        let response_code = easy.response_code().unwrap();
-        if response_code != 200 {
-            panic!(
-                "Unexpected response code {} for {}",
-                response_code, binary_url
-            );
-        }
+        download_with_retry(&binary_url, &file_name);
+        verify_sha256(&file_name, &binary_url);
     }
 
     // Extract the tarball.
@@ -272,6 +655,8 @@ fn install_prebuilt() {
         extract(file_name, &unpacked_dir);
     }
 
+    generate_bindings(&unpacked_dir.join("include"));
+
     #[cfg(not(target_env = "msvc"))] // There is no tensorflow_framework.dll
     println!("cargo:rustc-link-lib=dylib={}", FRAMEWORK_LIBRARY);
     println!("cargo:rustc-link-lib=dylib={}", LIBRARY);
@@ -297,6 +682,7 @@ fn install_prebuilt() {
         fs::copy(&library_full_path, &new_library_full_path).unwrap();
     }
     println!("cargo:rustc-link-search={}", output.display());
+    true
 }
 
 fn build_from_src() {
@@ -398,6 +784,8 @@ fn build_from_src() {
         fs::copy(target_bazel_bin, library_path).unwrap();
     }
 
+    generate_bindings(&source);
+
     println!("cargo:rustc-link-lib=dylib={}", FRAMEWORK_LIBRARY);
     println!("cargo:rustc-link-lib=dylib={}", LIBRARY);
     println!("cargo:rustc-link-search={}", lib_dir.display());